@@ -1,13 +1,26 @@
+use chrono::{DateTime, Duration, Utc};
 use clap::{App, Arg};
 use serde::{Deserialize, Serialize};
 use serde_json::json;
+use std::collections::HashMap;
 use std::fs;
 use std::future::Future;
 use std::io::{self, Write};
 use std::path::PathBuf;
+use std::process::{Command, Stdio};
 
 const BASE_URL: &str = "https://wap.tplinkcloud.com/";
 const DEFAULT_CONFIG_PATH: &str = ".tplink.toml";
+const KEYCHAIN_SERVICE: &str = "tplink-kasa-info";
+const KEYCHAIN_ACCOUNT: &str = "settings";
+/// How long a freshly fetched token is assumed to remain usable. The cloud does
+/// not advertise the lifetime, so we treat tokens older than this as stale and
+/// refresh them up front rather than waiting for the `-20651` error.
+const TOKEN_VALID_FOR_SECS: i64 = 60 * 60 * 24;
+/// A fixed application identifier sent alongside the terminal UUID so the cloud
+/// recognises every login of a stored profile as coming from the same
+/// registered terminal.
+const APP_TYPE: &str = "Kasa_Android";
 
 fn config_path(config_path_override: &Option<&str>) -> PathBuf {
     match config_path_override {
@@ -24,47 +37,259 @@ pub struct Settings {
     username: String,
     password: String,
     token: String,
+    #[serde(default)]
+    refresh_token: String,
+    #[serde(default)]
+    terminal_uuid: String,
+    #[serde(default = "default_issued_at")]
+    issued_at: DateTime<Utc>,
+    #[serde(default = "default_token_valid_for_secs")]
+    token_valid_for_secs: i64,
+}
+
+/// Configs written before the token lifecycle existed have no issuance time; we
+/// treat them as issued at the epoch so they read as stale and get refreshed.
+fn default_issued_at() -> DateTime<Utc> {
+    DateTime::<Utc>::from(std::time::UNIX_EPOCH)
+}
+
+fn default_token_valid_for_secs() -> i64 {
+    TOKEN_VALID_FOR_SECS
+}
+
+/// The on-disk configuration: a map of named account profiles plus the name of
+/// the one to use when `--profile` is omitted.
+#[derive(Deserialize, Serialize, Debug, Default)]
+pub struct Config {
+    #[serde(default)]
+    default_profile: Option<String>,
+    #[serde(default)]
+    profiles: HashMap<String, Settings>,
+}
+
+impl Config {
+    /// Pick the profile to act on: the explicit `--profile` name if given,
+    /// otherwise the configured default.
+    fn resolve_profile_name(&self, requested: &Option<&str>) -> String {
+        match requested {
+            Some(name) => name.to_string(),
+            None => self.default_profile.clone().unwrap_or_else(|| {
+                panic!("No profile requested and no default_profile set in the config")
+            }),
+        }
+    }
+}
+
+impl Settings {
+    /// A stored token is usable only while it is younger than its recorded
+    /// validity window and the wall clock has not moved backwards since it was
+    /// issued.
+    fn is_token_valid(&self) -> bool {
+        let age = Utc::now() - self.issued_at;
+        age >= Duration::zero() && age < Duration::seconds(self.token_valid_for_secs)
+    }
+}
+
+/// A backend that persists (and retrieves) the account secrets. The CLI never
+/// touches `fs` directly for credentials; everything routes through here so the
+/// token and password can live somewhere other than a plaintext file on disk.
+trait CredentialStore {
+    /// Read the stored config, or `None` if nothing has been stored yet.
+    fn read(&self) -> Option<Config>;
+    /// Persist the given config, overwriting whatever was there before.
+    fn write(&self, config: &Config);
+    /// Whether any config already exists.
+    fn exists(&self) -> bool {
+        self.read().is_some()
+    }
+}
+
+/// The original behaviour: a TOML document at `~/.tplink.toml` (or `--config`).
+struct TomlFileStore {
+    path: PathBuf,
+}
+
+impl CredentialStore for TomlFileStore {
+    fn read(&self) -> Option<Config> {
+        if self.path.exists() {
+            Some(toml::from_slice(&fs::read(&self.path).unwrap()).unwrap())
+        } else {
+            None
+        }
+    }
+
+    fn write(&self, config: &Config) {
+        let toml = toml::to_string(config).unwrap();
+        fs::write(&self.path, &toml).unwrap();
+    }
+
+    fn exists(&self) -> bool {
+        self.path.exists()
+    }
+}
+
+/// The OS keychain, via the `keyring` crate. The whole `Config` document is
+/// stored as a single JSON blob under a fixed service/account pair.
+struct KeychainStore;
+
+impl KeychainStore {
+    fn entry(&self) -> keyring::Entry {
+        keyring::Entry::new(KEYCHAIN_SERVICE, KEYCHAIN_ACCOUNT)
+    }
+}
+
+impl CredentialStore for KeychainStore {
+    fn read(&self) -> Option<Config> {
+        match self.entry().get_password() {
+            Ok(json) => Some(serde_json::from_str(&json).unwrap()),
+            Err(keyring::Error::NoEntry) => None,
+            Err(e) => panic!("Could not read from the OS keychain: {}", e),
+        }
+    }
+
+    fn write(&self, config: &Config) {
+        let json = serde_json::to_string(config).unwrap();
+        self.entry().set_password(&json).unwrap();
+    }
+}
+
+/// An external "credential-process" helper, modelled on Cargo's 1Password
+/// helper. The user configures a command; on read we run it and parse a JSON
+/// object (the serialized `Config`) from its stdout, and on write we pipe the
+/// same JSON to its stdin. This lets the secrets live in a vault the CLI never
+/// persists to disk itself.
+struct CredentialProcessStore {
+    command: String,
+}
+
+impl CredentialProcessStore {
+    fn spawn(&self, stdin: Stdio, stdout: Stdio) -> std::process::Child {
+        Command::new("sh")
+            .arg("-c")
+            .arg(&self.command)
+            .stdin(stdin)
+            .stdout(stdout)
+            .spawn()
+            .unwrap()
+    }
+}
+
+impl CredentialStore for CredentialProcessStore {
+    fn read(&self) -> Option<Config> {
+        let output = self
+            .spawn(Stdio::null(), Stdio::piped())
+            .wait_with_output()
+            .unwrap();
+        if !output.status.success() {
+            panic!(
+                "Credential process '{}' exited with status {}",
+                self.command, output.status
+            );
+        }
+        let stdout = String::from_utf8(output.stdout).unwrap();
+        let trimmed = stdout.trim();
+        if trimmed.is_empty() {
+            None
+        } else {
+            Some(serde_json::from_str(trimmed).unwrap())
+        }
+    }
+
+    fn write(&self, config: &Config) {
+        let json = serde_json::to_string(config).unwrap();
+        let mut child = self.spawn(Stdio::piped(), Stdio::null());
+        child
+            .stdin
+            .take()
+            .unwrap()
+            .write_all(json.as_bytes())
+            .unwrap();
+        let status = child.wait().unwrap();
+        if !status.success() {
+            panic!(
+                "Credential process '{}' exited with status {}",
+                self.command, status
+            );
+        }
+    }
+}
+
+fn credential_store(
+    config_path_override: &Option<&str>,
+    credential_store_override: &Option<&str>,
+) -> Box<dyn CredentialStore> {
+    match credential_store_override {
+        None | Some("toml") => Box::new(TomlFileStore {
+            path: config_path(config_path_override),
+        }),
+        Some("keychain") => Box::new(KeychainStore),
+        Some(spec) if spec.starts_with("process:") => Box::new(CredentialProcessStore {
+            command: spec["process:".len()..].to_string(),
+        }),
+        Some(other) => panic!(
+            "Unknown credential store '{}'. Valid values: toml, keychain, process:<command>",
+            other
+        ),
+    }
 }
 
 enum LoginDetails {
     Settings(Settings),
-    UsernameAndPassword(String, String),
+    UsernameAndPassword {
+        username: String,
+        password: String,
+        terminal_uuid: String,
+    },
 }
 
-fn write_settings(
-    config_path_override: &Option<&str>,
-    username: &str,
-    password: &str,
-    token: &str,
-) {
-    let settings = Settings {
-        username: username.to_owned(),
-        password: password.to_owned(),
-        token: token.to_owned(),
-    };
-    let toml = toml::to_string(&settings).unwrap();
-    let config_path = config_path(config_path_override);
-    fs::write(&config_path, &toml).unwrap();
+/// The outcome of a login or refresh: always a fresh access token, plus the
+/// refresh token if the cloud issued (or re-issued) one.
+struct NewToken {
+    token: String,
+    refresh_token: Option<String>,
 }
 
 async fn get_new_token(
-    config_path_override: &Option<&str>,
+    store: &dyn CredentialStore,
     login_details: &LoginDetails,
-) -> String {
-    eprintln!("Fetching new token");
-    let (username, password) = match login_details {
-        LoginDetails::Settings(s) => (&s.username, &s.password),
-        LoginDetails::UsernameAndPassword(u, p) => (u, p),
-    };
-    let request = json!({
-        "method": "login",
-        "params": {
-            "appType": "",
-            "cloudUserName": username,
-            "cloudPassword": password,
-            "terminalUUID": ""
+    profile_name: Option<&str>,
+) -> NewToken {
+    // Prefer exchanging a stored refresh token for a fresh access token; only
+    // replay the password when there is no refresh token to trade in.
+    let request = match login_details {
+        LoginDetails::Settings(s) if !s.refresh_token.is_empty() => {
+            eprintln!("Refreshing token");
+            json!({
+                "method": "refreshToken",
+                "params": {
+                    "appType": APP_TYPE,
+                    "terminalUUID": s.terminal_uuid,
+                    "refreshToken": s.refresh_token
+                }
+            })
+        }
+        _ => {
+            eprintln!("Fetching new token");
+            let (username, password, terminal_uuid) = match login_details {
+                LoginDetails::Settings(s) => (&s.username, &s.password, &s.terminal_uuid),
+                LoginDetails::UsernameAndPassword {
+                    username,
+                    password,
+                    terminal_uuid,
+                } => (username, password, terminal_uuid),
+            };
+            json!({
+                "method": "login",
+                "params": {
+                    "appType": APP_TYPE,
+                    "cloudUserName": username,
+                    "cloudPassword": password,
+                    "terminalUUID": terminal_uuid,
+                    "refreshTokenNeeded": true
+                }
+            })
         }
-    });
+    };
     let client = reqwest::Client::new();
     let response_text = client
         .post(BASE_URL)
@@ -83,18 +308,40 @@ async fn get_new_token(
     }
     let result = response["result"].as_object().unwrap();
     let token = result["token"].as_str().unwrap();
-    if let LoginDetails::Settings(_) = login_details {
-        write_settings(config_path_override, username, password, token);
+    let refresh_token = result["refreshToken"].as_str().map(String::from);
+    if let LoginDetails::Settings(s) = login_details {
+        let name = profile_name.expect("refreshing a stored profile requires its name");
+        let mut config = store.read().unwrap_or_default();
+        config.profiles.insert(
+            name.to_string(),
+            Settings {
+                username: s.username.clone(),
+                // The password is never persisted again, whether or not the
+                // cloud issued a refresh token; re-auth relies on the
+                // longer-lived credential.
+                password: String::new(),
+                token: token.to_string(),
+                refresh_token: refresh_token.clone().unwrap_or_else(|| s.refresh_token.clone()),
+                terminal_uuid: s.terminal_uuid.clone(),
+                issued_at: Utc::now(),
+                token_valid_for_secs: TOKEN_VALID_FOR_SECS,
+            },
+        );
+        store.write(&config);
+    };
+    return NewToken {
+        token: String::from(token),
+        refresh_token,
     };
-    return String::from(token);
 }
 
-async fn setup(config_path_override: &Option<&str>, overwrite: bool) {
-    let config_path = config_path(config_path_override);
-    if overwrite == false && config_path.exists() {
+async fn setup(store: &dyn CredentialStore, profile: &Option<&str>, overwrite: bool) {
+    let profile_name = profile.unwrap_or("default").to_string();
+    let mut config = store.read().unwrap_or_default();
+    if overwrite == false && config.profiles.contains_key(&profile_name) {
         panic!(
-            "A config already exists at {}. Please remove it if first before running setup again",
-            config_path.display()
+            "A profile named '{}' already exists. Pass -o to replace it.",
+            profile_name
         );
     }
     fn prompt(text: &str) -> String {
@@ -106,35 +353,76 @@ async fn setup(config_path_override: &Option<&str>, overwrite: bool) {
     }
     let username = prompt("Enter your tp-link kasa username");
     let password = prompt("Enter your tp-link kasa password");
-    let token = get_new_token(
-        config_path_override,
-        &LoginDetails::UsernameAndPassword(username.clone(), password.clone()),
+    // Register this CLI as a single persistent terminal on the account so the
+    // cloud hands out longer-lived tokens and the same identity is re-used
+    // across every subsequent login.
+    let terminal_uuid = uuid::Uuid::new_v4().to_string();
+    let new_token = get_new_token(
+        store,
+        &LoginDetails::UsernameAndPassword {
+            username: username.clone(),
+            password,
+            terminal_uuid: terminal_uuid.clone(),
+        },
+        None,
     )
     .await;
-    write_settings(config_path_override, &username, &password, &token);
+    // Merge this profile into whatever is already stored rather than clobbering
+    // the other accounts, and make it the default if none is set yet. The
+    // password was used exactly once above and is deliberately not persisted;
+    // re-auth relies on the refresh token instead.
+    config.profiles.insert(
+        profile_name.clone(),
+        Settings {
+            username,
+            password: String::new(),
+            token: new_token.token,
+            refresh_token: new_token.refresh_token.unwrap_or_default(),
+            terminal_uuid,
+            issued_at: Utc::now(),
+            token_valid_for_secs: TOKEN_VALID_FOR_SECS,
+        },
+    );
+    if config.default_profile.is_none() {
+        config.default_profile = Some(profile_name);
+    }
+    store.write(&config);
 }
 
 async fn runner(
     request: serde_json::value::Value,
     arg_matches: &clap::ArgMatches<'_>,
 ) -> serde_json::value::Value {
-    let config_path_override = arg_matches.value_of("config");
-    let login_details = match (
+    let store = credential_store(
+        &arg_matches.value_of("config"),
+        &arg_matches.value_of("credential-store"),
+    );
+    let profile_arg = arg_matches.value_of("profile");
+    let (login_details, profile_name): (LoginDetails, Option<String>) = match (
         arg_matches.value_of("username"),
         arg_matches.value_of("password"),
     ) {
         (Some(_), None) | (None, Some(_)) => {
             panic!("You must pass both a username and password, or neither");
         }
-        (Some(u), Some(p)) => LoginDetails::UsernameAndPassword(String::from(u), String::from(p)),
+        (Some(u), Some(p)) => (
+            LoginDetails::UsernameAndPassword {
+                username: String::from(u),
+                password: String::from(p),
+                terminal_uuid: uuid::Uuid::new_v4().to_string(),
+            },
+            None,
+        ),
         (None, None) => {
-            let config_path = config_path(&config_path_override);
-            if config_path.exists() {
-                let settings: Settings = toml::from_slice(&fs::read(config_path).unwrap()).unwrap();
-                LoginDetails::Settings(settings)
-            } else {
-                panic!("Config does not exist at {}. Either run the setup command, or pass a username and password via command-line flags", config_path.display());
-            }
+            let mut config = store.read().unwrap_or_else(|| {
+                panic!("No stored credentials found. Either run the setup command, or pass a username and password via command-line flags")
+            });
+            let name = config.resolve_profile_name(&profile_arg);
+            let settings = config
+                .profiles
+                .remove(&name)
+                .unwrap_or_else(|| panic!("No profile named '{}' in the config", name));
+            (LoginDetails::Settings(settings), Some(name))
         }
     };
     enum ApiResult {
@@ -167,11 +455,12 @@ async fn runner(
     };
     async fn fetch_token_and_go<T: Future<Output = ApiResult>>(
         request: serde_json::value::Value,
-        config_path_override: &Option<&str>,
+        store: &dyn CredentialStore,
         login_details: &LoginDetails,
+        profile_name: Option<&str>,
         go: fn(serde_json::value::Value, String) -> T,
     ) -> serde_json::value::Value {
-        let token = get_new_token(config_path_override, login_details).await;
+        let token = get_new_token(store, login_details, profile_name).await.token;
         match go(request, token).await {
             ApiResult::Success(r) => r,
             ApiResult::TokenExpired => panic!("Token is supposedly expired but we just got it"),
@@ -180,17 +469,43 @@ async fn runner(
     };
     match login_details {
         LoginDetails::Settings(ref s) => {
+            if !s.is_token_valid() {
+                // The stored token has aged out; refresh up front instead of
+                // spending a guaranteed-failing round trip to discover it.
+                return fetch_token_and_go(
+                    request,
+                    store.as_ref(),
+                    &login_details,
+                    profile_name.as_deref(),
+                    go,
+                )
+                .await;
+            }
             let request_clone = request.clone();
             match go(request_clone, s.token.clone()).await {
                 ApiResult::Success(r) => r,
                 ApiResult::TokenExpired => {
-                    fetch_token_and_go(request, &config_path_override, &login_details, go).await
+                    fetch_token_and_go(
+                        request,
+                        store.as_ref(),
+                        &login_details,
+                        profile_name.as_deref(),
+                        go,
+                    )
+                    .await
                 }
                 ApiResult::Error(e) => panic!(e),
             }
         }
-        LoginDetails::UsernameAndPassword(_, _) => {
-            fetch_token_and_go(request, &config_path_override, &login_details, go).await
+        LoginDetails::UsernameAndPassword { .. } => {
+            fetch_token_and_go(
+                request,
+                store.as_ref(),
+                &login_details,
+                profile_name.as_deref(),
+                go,
+            )
+            .await
         }
     }
 }
@@ -235,8 +550,20 @@ async fn main() {
         .short("c")
         .value_name("CONFIG")
         .help(&config_help);
+    let credential_store_arg = Arg::with_name("credential-store")
+        .long("credential-store")
+        .value_name("STORE")
+        .help("Where to store credentials: toml (default), keychain, or process:<command>")
+        .takes_value(true);
+    let profile_arg = Arg::with_name("profile")
+        .long("profile")
+        .value_name("NAME")
+        .help("Which account profile to use (default: the config's default_profile; for setup: \"default\")")
+        .takes_value(true);
     let common_args = [
         config_arg.clone(),
+        credential_store_arg.clone(),
+        profile_arg.clone(),
         Arg::with_name("username")
             .short("u")
             .value_name("USERNAME")
@@ -270,6 +597,8 @@ async fn main() {
             App::new("setup")
                 .about("Stores username and password in a settings file")
                 .arg(&config_arg)
+                .arg(&credential_store_arg)
+                .arg(&profile_arg)
                 .arg(
                     Arg::with_name("overwrite")
                         .short("o")
@@ -286,9 +615,12 @@ async fn main() {
             print_device_list(submatches).await;
         }
         ("setup", Some(submatches)) => {
-            let config_path = submatches.value_of("config");
+            let store = credential_store(
+                &submatches.value_of("config"),
+                &submatches.value_of("credential-store"),
+            );
             let overwrite = submatches.is_present("overwrite");
-            setup(&config_path, overwrite).await;
+            setup(store.as_ref(), &submatches.value_of("profile"), overwrite).await;
         }
         _ => panic!("Unreachable branch due to clap::AppSettings::ArgRequiredElseHelp"),
     }